@@ -15,7 +15,7 @@ use mem;
 use ptr;
 use prelude::*;
 use super::{last_error, last_net_error, retry, sock_t};
-use sync::{Arc, atomic};
+use sync::{Arc, Mutex, atomic};
 use sys::fs::FileDesc;
 use sys::{mod, c, set_nonblocking, wouldblock, timer};
 use sys_common::{mod, timeout, eof};
@@ -44,20 +44,90 @@ impl Drop for Event {
     }
 }
 
+// Helper for the handful of integer socket options we set below.
+// `value` is passed by-value and its size is taken from its type, so
+// callers hand in a plain `c_int` flag (or, for `SO_UPDATE_ACCEPT_CONTEXT`,
+// the listener's `SOCKET` handle) instead of fiddling with pointers
+// themselves. Named distinctly from `sys_common::net::setsockopt` (in scope
+// via the glob import above) since that one takes its value differently.
+fn set_sock_opt<T>(fd: sock_t, level: libc::c_int, name: libc::c_int,
+                    value: T) -> IoResult<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd, level, name,
+                          &value as *const _ as *const libc::c_void,
+                          mem::size_of::<T>() as libc::socklen_t)
+    };
+    if ret == 0 { Ok(()) } else { Err(last_net_error()) }
+}
+
+// Windows has no per-socket `TCP_KEEPIDLE`/`TCP_KEEPINTVL`, so keepalive is
+// configured wholesale through the `SIO_KEEPALIVE_VALS` WSAIoctl instead of
+// `set_sock_opt`.
+fn set_keepalive(fd: sock_t, keepalive: Option<u32>) -> IoResult<()> {
+    let ms = keepalive.map(|secs| secs * 1000).unwrap_or(0) as libc::c_ulong;
+    let mut vals = c::tcp_keepalive {
+        onoff: keepalive.is_some() as libc::c_ulong,
+        keepalivetime: ms,
+        keepaliveinterval: ms,
+    };
+    let mut bytes = 0;
+    let ret = unsafe {
+        c::WSAIoctl(fd, c::SIO_KEEPALIVE_VALS,
+                    &mut vals as *mut _ as *mut libc::c_void,
+                    mem::size_of_val(&vals) as libc::DWORD,
+                    ptr::null_mut(), 0, &mut bytes,
+                    ptr::null_mut(), None)
+    };
+    if ret == 0 { Ok(()) } else { Err(last_net_error()) }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // TCP listeners
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct TcpListener {
     inner: FileDesc,
+    nodelay: bool,
+    keepalive: Option<u32>,
+    family: libc::c_int,
 }
 
 impl TcpListener {
     pub fn bind(addr: ip::SocketAddr) -> IoResult<TcpListener> {
+        TcpListener::bind_with_options(addr, false, None)
+    }
+
+    /// Like `bind`, but applies `SO_REUSEADDR` (when `reuse_addr` is true)
+    /// and `IPV6_V6ONLY` (when `only_v6` is `Some` and `addr` is an IPv6
+    /// address) between `socket()` and `bind()`, as required for either to
+    /// take effect. `only_v6` of `None` leaves the OS default untouched, so
+    /// a single `AF_INET6` listener can be made to also accept IPv4-mapped
+    /// connections by passing `Some(false)`.
+    pub fn bind_with_options(addr: ip::SocketAddr, reuse_addr: bool,
+                              only_v6: Option<bool>) -> IoResult<TcpListener> {
         sys::init_net();
 
         let fd = try!(socket(addr, libc::SOCK_STREAM));
-        let ret = TcpListener { inner: FileDesc::new(fd as libc::c_int, true) };
+        let family = match addr.ip {
+            ip::Ipv4Addr(..) => libc::AF_INET,
+            ip::Ipv6Addr(..) => libc::AF_INET6,
+        };
+        let ret = TcpListener {
+            inner: FileDesc::new(fd as libc::c_int, true),
+            nodelay: false,
+            keepalive: None,
+            family: family,
+        };
+
+        if reuse_addr {
+            try!(ret.set_reuse_address(true));
+        }
+        if let Some(only_v6) = only_v6 {
+            match addr.ip {
+                ip::Ipv6Addr(..) => try!(ret.set_only_v6(only_v6)),
+                ip::Ipv4Addr(..) => {}
+            }
+        }
 
         let mut storage = unsafe { mem::zeroed() };
         let len = addr_to_sockaddr(addr, &mut storage);
@@ -71,6 +141,35 @@ impl TcpListener {
 
     pub fn fd(&self) -> sock_t { self.inner.fd as sock_t }
 
+    pub fn set_only_v6(&self, only_v6: bool) -> IoResult<()> {
+        set_sock_opt(self.fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY,
+                   only_v6 as libc::c_int)
+    }
+
+    pub fn set_reuse_address(&self, reuse: bool) -> IoResult<()> {
+        set_sock_opt(self.fd(), libc::SOL_SOCKET, libc::SO_REUSEADDR,
+                   reuse as libc::c_int)
+    }
+
+    /// Sets `TCP_NODELAY` on the listening socket and records it as the
+    /// default that accepted sockets will inherit.
+    pub fn set_nodelay(&mut self, enable: bool) -> IoResult<()> {
+        try!(set_sock_opt(self.fd(), libc::IPPROTO_TCP, libc::TCP_NODELAY,
+                         enable as libc::c_int));
+        self.nodelay = enable;
+        Ok(())
+    }
+
+    /// Enables TCP keepalive through the `SIO_KEEPALIVE_VALS` ioctl (with the
+    /// given idle delay, in seconds, also used as the probe interval), or
+    /// disables it for `None`, on the listening socket and records it as the
+    /// default that accepted sockets will inherit.
+    pub fn set_keepalive(&mut self, delay_in_seconds: Option<u32>) -> IoResult<()> {
+        try!(set_keepalive(self.fd(), delay_in_seconds));
+        self.keepalive = delay_in_seconds;
+        Ok(())
+    }
+
     pub fn listen(self, backlog: int) -> IoResult<TcpAcceptor> {
         match unsafe { libc::listen(self.fd(), backlog as libc::c_int) } {
             -1 => Err(last_net_error()),
@@ -83,12 +182,16 @@ impl TcpListener {
                 if ret != 0 {
                     return Err(last_net_error())
                 }
+                let nodelay = self.nodelay;
+                let keepalive = self.keepalive;
                 Ok(TcpAcceptor {
                     inner: Arc::new(AcceptorInner {
                         listener: self,
                         abort: try!(Event::new()),
                         accept: accept,
                         closed: atomic::AtomicBool::new(false),
+                        nodelay: nodelay,
+                        keepalive: keepalive,
                     }),
                     deadline: 0,
                 })
@@ -111,12 +214,18 @@ struct AcceptorInner {
     abort: Event,
     accept: Event,
     closed: atomic::AtomicBool,
+    nodelay: bool,
+    keepalive: Option<u32>,
 }
 
 impl TcpAcceptor {
     pub fn fd(&self) -> sock_t { self.inner.listener.fd() }
 
     pub fn accept(&mut self) -> IoResult<TcpStream> {
+        self.accept_with_addr().map(|(stream, _)| stream)
+    }
+
+    pub fn accept_with_addr(&mut self) -> IoResult<(TcpStream, ip::SocketAddr)> {
         // Unlink unix, windows cannot invoke `select` on arbitrary file
         // descriptors like pipes, only sockets. Consequently, windows cannot
         // use the same implementation as unix for accept() when close_accept()
@@ -166,8 +275,12 @@ impl TcpAcceptor {
             if ret != 0 { return Err(last_net_error()) }
 
             if wsaevents.lNetworkEvents & c::FD_ACCEPT == 0 { continue }
+            let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
             match unsafe {
-                libc::accept(self.fd(), ptr::null_mut(), ptr::null_mut())
+                libc::accept(self.fd(),
+                              &mut storage as *mut _ as *mut libc::sockaddr,
+                              &mut len)
             } {
                 -1 if wouldblock() => {}
                 -1 => return Err(last_net_error()),
@@ -176,13 +289,21 @@ impl TcpAcceptor {
                 // so we need to deregister our event and switch the socket back
                 // to blocking mode
                 fd => {
+                    let addr = try!(sockaddr_to_addr(&storage, len as uint));
+                    if self.inner.nodelay {
+                        try!(set_sock_opt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY,
+                                         1 as libc::c_int));
+                    }
+                    if self.inner.keepalive.is_some() {
+                        try!(set_keepalive(fd, self.inner.keepalive));
+                    }
                     let stream = TcpStream::new(fd);
                     let ret = unsafe {
                         c::WSAEventSelect(fd, events[1], 0)
                     };
                     if ret != 0 { return Err(last_net_error()) }
                     try!(set_nonblocking(fd, false));
-                    return Ok(stream)
+                    return Ok((stream, addr))
                 }
             }
         }
@@ -217,3 +338,414 @@ impl Clone for TcpAcceptor {
         }
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Scalable IOCP-based acceptor
+////////////////////////////////////////////////////////////////////////////////
+
+// `TcpAcceptor` above spends one thread per acceptor blocked in
+// `WSAWaitForMultipleEvents`, which doesn't scale to servers juggling
+// thousands of listeners. `IocpAcceptor` is an alternate backend for the
+// same `TcpListener` that instead keeps a handful of `AcceptEx` calls
+// perpetually in flight against an I/O completion port, so a single thread
+// (or a small pool of them) can dequeue completed connections for any number
+// of listeners with `GetQueuedCompletionStatus`.
+
+// Number of `AcceptEx` calls kept outstanding at once. Each one backs a
+// pre-created socket that becomes the next accepted connection, so this
+// bounds how many connections can complete between two dequeues.
+const ACCEPT_EX_BACKLOG: uint = 8;
+
+// Per MSDN, `AcceptEx`'s output buffer must hold the local and remote
+// addresses back to back, each padded with an extra 16 bytes beyond the
+// largest possible `sockaddr`.
+const ACCEPT_ADDR_SIZE: uint = mem::size_of::<libc::sockaddr_storage>() + 16;
+
+// Completion key used to tell a real `AcceptEx` completion apart from the
+// sentinel packet posted by `close_accept`. `GetQueuedCompletionStatus`
+// leaves `lpCompletionKey` untouched on a plain wait timeout, so this must
+// not be `0` (the zeroed-out value `key` starts from) or a timeout would be
+// misread as an abort.
+const CK_ACCEPT: libc::ULONG_PTR = 1;
+const CK_ABORT: libc::ULONG_PTR = 2;
+
+#[repr(C)]
+struct AcceptExOverlapped {
+    // Must stay the first field: a raw `*mut OVERLAPPED` handed back by
+    // `GetQueuedCompletionStatus` is transmuted straight back into a
+    // `*mut AcceptExOverlapped`, which only holds if `overlapped` sits at
+    // offset 0.
+    overlapped: c::OVERLAPPED,
+    socket: sock_t,
+    addrs: [u8, ..ACCEPT_ADDR_SIZE * 2],
+}
+
+pub struct IocpAcceptor {
+    inner: Arc<IocpAcceptorInner>,
+    deadline: u64,
+}
+
+struct IocpAcceptorInner {
+    listener: TcpListener,
+    port: c::HANDLE,
+    accept_ex: c::LPFN_ACCEPTEX,
+    get_accept_ex_sockaddrs: c::LPFN_GETACCEPTEXSOCKADDRS,
+    closed: atomic::AtomicBool,
+    // Sockets backing an `AcceptEx` call that hasn't completed yet, so
+    // teardown can cancel and reclaim them instead of leaking.
+    pending: Mutex<Vec<sock_t>>,
+}
+
+impl IocpAcceptor {
+    /// Takes over a bound `TcpListener` and switches it to the `AcceptEx` +
+    /// IOCP backend instead of `WSAWaitForMultipleEvents`; this is an
+    /// alternative to `TcpListener::listen`, not something layered on top of
+    /// it, so it puts the socket into the listening state itself.
+    pub fn new(listener: TcpListener) -> IoResult<IocpAcceptor> {
+        match unsafe { libc::listen(listener.fd(), 128) } {
+            -1 => return Err(last_net_error()),
+            _ => {}
+        }
+
+        let port = unsafe {
+            c::CreateIoCompletionPort(c::INVALID_HANDLE_VALUE,
+                                       ptr::null_mut(), 0, 0)
+        };
+        if port.is_null() { return Err(last_error()) }
+
+        let ret = unsafe {
+            c::CreateIoCompletionPort(listener.fd() as c::HANDLE, port,
+                                       CK_ACCEPT, 0)
+        };
+        if ret.is_null() { return Err(last_error()) }
+
+        let accept_ex = try!(lookup_accept_ex(listener.fd()));
+        let get_accept_ex_sockaddrs = try!(lookup_get_accept_ex_sockaddrs(listener.fd()));
+
+        let acceptor = IocpAcceptor {
+            inner: Arc::new(IocpAcceptorInner {
+                listener: listener,
+                port: port,
+                accept_ex: accept_ex,
+                get_accept_ex_sockaddrs: get_accept_ex_sockaddrs,
+                closed: atomic::AtomicBool::new(false),
+                pending: Mutex::new(Vec::with_capacity(ACCEPT_EX_BACKLOG)),
+            }),
+            deadline: 0,
+        };
+        for _ in range(0u, ACCEPT_EX_BACKLOG) {
+            try!(acceptor.post_accept());
+        }
+        Ok(acceptor)
+    }
+
+    pub fn fd(&self) -> sock_t { self.inner.listener.fd() }
+
+    // Creates a fresh socket of the listener's family and hands it to
+    // `AcceptEx`, which will bind an inbound connection to it in the
+    // background. The context pointer stashed inside the overlapped
+    // structure is reclaimed by `accept_with_addr` (or torn down by
+    // `IocpAcceptorInner`'s `Drop`) once the completion port reports this
+    // I/O as finished.
+    fn post_accept(&self) -> IoResult<()> {
+        let fd = unsafe {
+            libc::socket(self.inner.listener.family, libc::SOCK_STREAM, 0)
+        };
+        if fd == -1 as libc::SOCKET { return Err(last_net_error()) }
+
+        let ctx = box AcceptExOverlapped {
+            overlapped: unsafe { mem::zeroed() },
+            socket: fd as sock_t,
+            addrs: [0, ..ACCEPT_ADDR_SIZE * 2],
+        };
+        let ctx = unsafe { mem::transmute::<_, *mut AcceptExOverlapped>(ctx) };
+
+        // Record this socket as outstanding *before* calling `AcceptEx`.
+        // This backend is meant to be shared by a pool of threads, and a
+        // synchronously-completed accept (a connection already sitting in
+        // the listen backlog) can be dequeued by another thread's
+        // `accept_with_addr` before this call even returns. If `pending`
+        // didn't already list it, that thread's `retain` would be a no-op
+        // and leave a phantom entry behind for `Drop` to choke on.
+        self.inner.pending.lock().push(fd as sock_t);
+
+        let mut bytes = 0;
+        let ret = unsafe {
+            (self.inner.accept_ex)(self.fd(),
+                                    fd,
+                                    (*ctx).addrs.as_mut_ptr() as *mut libc::c_void,
+                                    0,
+                                    ACCEPT_ADDR_SIZE as libc::DWORD,
+                                    ACCEPT_ADDR_SIZE as libc::DWORD,
+                                    &mut bytes,
+                                    &mut (*ctx).overlapped)
+        };
+        // The normal case for an overlapped `AcceptEx` is `FALSE` paired
+        // with `ERROR_IO_PENDING` -- the connection just hasn't arrived
+        // yet. Only anything else is a real failure.
+        if ret == libc::FALSE && unsafe { libc::GetLastError() } != c::ERROR_IO_PENDING as libc::DWORD {
+            let err = last_net_error();
+            self.inner.pending.lock().retain(|&s| s != fd as sock_t);
+            unsafe {
+                let _ctx: Box<AcceptExOverlapped> = mem::transmute(ctx);
+                let _ = libc::closesocket(fd);
+            }
+            return Err(err)
+        }
+        Ok(())
+    }
+
+    pub fn accept(&mut self) -> IoResult<TcpStream> {
+        self.accept_with_addr().map(|(stream, _)| stream)
+    }
+
+    pub fn accept_with_addr(&mut self) -> IoResult<(TcpStream, ip::SocketAddr)> {
+        // Mirrors `TcpAcceptor::accept_with_addr`'s loop guard: don't rely solely on
+        // dequeuing the re-posted `CK_ABORT` sentinel ahead of a racing
+        // real connection, since an already-closed acceptor should
+        // reliably report `eof()`.
+        if self.inner.closed.load(atomic::SeqCst) {
+            return Err(eof())
+        }
+
+        let ms = if self.deadline == 0 {
+            c::WSA_INFINITE as libc::DWORD
+        } else {
+            let now = timer::now();
+            if self.deadline < now {0} else {(self.deadline - now) as libc::DWORD}
+        };
+
+        let mut bytes = 0;
+        let mut key = 0;
+        let mut overlapped = ptr::null_mut();
+        let ret = unsafe {
+            c::GetQueuedCompletionStatus(self.inner.port, &mut bytes, &mut key,
+                                          &mut overlapped, ms)
+        };
+
+        // `GetQueuedCompletionStatus` leaves `key`/`overlapped` untouched
+        // when it simply times out, so that must be ruled out before `key`
+        // is trusted at all.
+        if ret == libc::FALSE && overlapped.is_null() {
+            return Err(timeout("accept timed out"))
+        }
+
+        if key == CK_ABORT {
+            // The sentinel posted by `close_accept`; re-post it so any
+            // sibling `IocpAcceptor` handles (see `Clone`) also wake up.
+            unsafe {
+                c::PostQueuedCompletionStatus(self.inner.port, 0, CK_ABORT,
+                                               ptr::null_mut());
+            }
+            return Err(eof())
+        }
+
+        let ctx: Box<AcceptExOverlapped> = unsafe { mem::transmute(overlapped) };
+        let fd = ctx.socket;
+        self.inner.pending.lock().retain(|&s| s != fd);
+
+        if ret == libc::FALSE {
+            // A genuine failed completion (e.g. the pre-created socket was
+            // reset before a connection arrived). Capture the error right
+            // away -- `post_accept` below makes its own syscalls (`socket`,
+            // `AcceptEx`) that would otherwise clobber the thread's last
+            // error before we get to report it. Reclaim the dead socket and
+            // keep the backlog topped up, but don't let a failure in that
+            // best-effort replenishment shadow the real error.
+            let err = last_net_error();
+            unsafe { let _ = libc::closesocket(fd); }
+            let _ = self.post_accept();
+            return Err(err)
+        }
+
+        // `SO_UPDATE_ACCEPT_CONTEXT` wants the listening socket's full
+        // `SOCKET` handle, not a truncated `c_int`, so pass `self.fd()`
+        // (already `sock_t`-typed) straight through.
+        try!(set_sock_opt(fd, libc::SOL_SOCKET, c::SO_UPDATE_ACCEPT_CONTEXT,
+                           self.fd()));
+
+        // Parse the local/remote `sockaddr`s back out of the buffer
+        // `AcceptEx` filled in via the documented `GetAcceptExSockaddrs`,
+        // rather than assuming their offset and length ourselves.
+        let mut local_addr: *mut libc::sockaddr = ptr::null_mut();
+        let mut local_len: libc::c_int = 0;
+        let mut remote_addr: *mut libc::sockaddr = ptr::null_mut();
+        let mut remote_len: libc::c_int = 0;
+        unsafe {
+            (self.inner.get_accept_ex_sockaddrs)(
+                ctx.addrs.as_ptr() as *mut libc::c_void,
+                0,
+                ACCEPT_ADDR_SIZE as libc::DWORD,
+                ACCEPT_ADDR_SIZE as libc::DWORD,
+                &mut local_addr, &mut local_len,
+                &mut remote_addr, &mut remote_len);
+        }
+        let storage = unsafe { &*(remote_addr as *const libc::sockaddr_storage) };
+        let addr = try!(sockaddr_to_addr(storage, remote_len as uint));
+
+        // Inherit the listener's configured defaults, same as the
+        // `WSAWaitForMultipleEvents`-based `TcpAcceptor` does.
+        if self.inner.listener.nodelay {
+            try!(set_sock_opt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY,
+                               1 as libc::c_int));
+        }
+        if self.inner.listener.keepalive.is_some() {
+            try!(set_keepalive(fd, self.inner.listener.keepalive));
+        }
+
+        try!(set_nonblocking(fd, false));
+        try!(self.post_accept());
+
+        Ok((TcpStream::new(fd as libc::c_int), addr))
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<u64>) {
+        self.deadline = timeout.map(|a| timer::now() + a).unwrap_or(0);
+    }
+
+    pub fn close_accept(&mut self) -> IoResult<()> {
+        self.inner.closed.store(true, atomic::SeqCst);
+        let ret = unsafe {
+            c::PostQueuedCompletionStatus(self.inner.port, 0, CK_ABORT,
+                                           ptr::null_mut())
+        };
+        if ret != 0 {
+            Ok(())
+        } else {
+            Err(last_net_error())
+        }
+    }
+}
+
+impl Clone for IocpAcceptor {
+    fn clone(&self) -> IocpAcceptor {
+        IocpAcceptor {
+            inner: self.inner.clone(),
+            deadline: 0,
+        }
+    }
+}
+
+impl Drop for IocpAcceptorInner {
+    fn drop(&mut self) {
+        // Cancel every still-outstanding `AcceptEx` and drain its
+        // completion from the port so the boxed context and pre-created
+        // socket backing it are freed instead of leaked.
+        let pending = mem::replace(&mut *self.pending.lock(), Vec::new());
+        for &fd in pending.iter() {
+            unsafe { let _ = c::CancelIoEx(fd as c::HANDLE, ptr::null_mut()); }
+        }
+
+        // Drain exactly as many *real* completions as there are cancelled
+        // sockets to reclaim, skipping over (without counting) any leftover
+        // `CK_ABORT` sentinel from `close_accept` -- a null-overlapped
+        // completion doesn't correspond to one of the cancelled I/Os, so
+        // treating it as one would leave a real completion undrained and
+        // its context/socket leaked. Cancelled I/O is guaranteed to
+        // complete, but nothing guarantees it does so before the port is
+        // torn down, so each wait is bounded rather than infinite.
+        let mut remaining = pending.len();
+        while remaining > 0 {
+            let mut bytes = 0;
+            let mut key = 0;
+            let mut overlapped = ptr::null_mut();
+            let ret = unsafe {
+                c::GetQueuedCompletionStatus(self.port, &mut bytes, &mut key,
+                                              &mut overlapped, 5000)
+            };
+            if ret == libc::FALSE && overlapped.is_null() {
+                // Gave up waiting for a cancelled accept to complete;
+                // better to leak it than hang the dropping thread forever.
+                break
+            }
+            if key == CK_ABORT { continue }
+            remaining -= 1;
+            let ctx: Box<AcceptExOverlapped> = unsafe { mem::transmute(overlapped) };
+            unsafe { let _ = libc::closesocket(ctx.socket); }
+        }
+        unsafe { let _ = libc::CloseHandle(self.port); }
+    }
+}
+
+// Resolves `AcceptEx`'s function pointer via the `WSAID_ACCEPTEX` GUID; it
+// isn't exported by any import library and must be looked up per-socket
+// with `WSAIoctl(SIO_GET_EXTENSION_FUNCTION_POINTER)`.
+fn lookup_accept_ex(fd: sock_t) -> IoResult<c::LPFN_ACCEPTEX> {
+    let mut accept_ex: c::LPFN_ACCEPTEX = unsafe { mem::zeroed() };
+    let mut guid = c::WSAID_ACCEPTEX;
+    let mut bytes = 0;
+    let ret = unsafe {
+        c::WSAIoctl(fd,
+                    c::SIO_GET_EXTENSION_FUNCTION_POINTER,
+                    &mut guid as *mut _ as *mut libc::c_void,
+                    mem::size_of_val(&guid) as libc::DWORD,
+                    &mut accept_ex as *mut _ as *mut libc::c_void,
+                    mem::size_of_val(&accept_ex) as libc::DWORD,
+                    &mut bytes,
+                    ptr::null_mut(),
+                    None)
+    };
+    if ret == 0 { Ok(accept_ex) } else { Err(last_net_error()) }
+}
+
+// Same lookup dance as `lookup_accept_ex`, for the extension function that
+// parses `AcceptEx`'s output address buffer.
+fn lookup_get_accept_ex_sockaddrs(fd: sock_t) -> IoResult<c::LPFN_GETACCEPTEXSOCKADDRS> {
+    let mut f: c::LPFN_GETACCEPTEXSOCKADDRS = unsafe { mem::zeroed() };
+    let mut guid = c::WSAID_GETACCEPTEXSOCKADDRS;
+    let mut bytes = 0;
+    let ret = unsafe {
+        c::WSAIoctl(fd,
+                    c::SIO_GET_EXTENSION_FUNCTION_POINTER,
+                    &mut guid as *mut _ as *mut libc::c_void,
+                    mem::size_of_val(&guid) as libc::DWORD,
+                    &mut f as *mut _ as *mut libc::c_void,
+                    mem::size_of_val(&f) as libc::DWORD,
+                    &mut bytes,
+                    ptr::null_mut(),
+                    None)
+    };
+    if ret == 0 { Ok(f) } else { Err(last_net_error()) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TcpListener, IocpAcceptor};
+    use io::net::ip::{SocketAddr, Ipv4Addr};
+    use libc;
+    use mem;
+    use sys_common::net::addr_to_sockaddr;
+
+    fn next_test_ip4() -> SocketAddr {
+        SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 }
+    }
+
+    #[test]
+    fn smoke_accept_connect_timeout_close() {
+        let mut listener = TcpListener::bind(next_test_ip4()).unwrap();
+        let addr = listener.socket_name().unwrap();
+        let mut acceptor = IocpAcceptor::new(listener).unwrap();
+
+        // Nothing has connected yet, so a short deadline should time out
+        // rather than hang forever or get mistaken for `close_accept`.
+        acceptor.set_timeout(Some(50));
+        assert!(acceptor.accept().is_err());
+
+        // A real connection should come back out the other end.
+        acceptor.set_timeout(None);
+        let client = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        assert!(client >= 0);
+        let mut storage = unsafe { mem::zeroed() };
+        let len = addr_to_sockaddr(addr, &mut storage);
+        let addrp = &storage as *const _ as *const libc::sockaddr;
+        assert_eq!(unsafe { libc::connect(client, addrp, len) }, 0);
+        assert!(acceptor.accept().is_ok());
+        unsafe { libc::closesocket(client as libc::SOCKET); }
+
+        // `close_accept` should wake a blocked `accept` rather than leaving
+        // it hanging until the next (nonexistent) connection.
+        let mut other = acceptor.clone();
+        other.close_accept().unwrap();
+        assert!(acceptor.accept().is_err());
+    }
+}